@@ -0,0 +1,62 @@
+//! The main module and entrypoint
+//!
+//! The operating system and app also starts in this file
+
+#![deny(missing_docs)]
+#![deny(warnings)]
+#![no_std]
+#![no_main]
+#![feature(panic_info_message)]
+
+use core::arch::global_asm;
+
+extern crate alloc;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+mod console;
+mod config;
+mod lang_items;
+mod logging;
+mod loader;
+mod mm;
+mod sbi;
+mod sync;
+mod syscall;
+mod task;
+mod timer;
+mod trap;
+
+global_asm!(include_str!("entry.asm"));
+global_asm!(include_str!("link_app.S"));
+
+/// clear BSS segment
+fn clear_bss() {
+    extern "C" {
+        fn sbss();
+        fn ebss();
+    }
+    (sbss as usize..ebss as usize).for_each(|a| unsafe {
+        (a as *mut u8).write_volatile(0);
+    });
+}
+
+#[no_mangle]
+/// the rust entry-point of os
+pub fn rust_main() -> ! {
+    clear_bss();
+    logging::init();
+    println!("[kernel] Hello, world!");
+    mm::init();
+    trap::init();
+    // Without this the ready queue draining never gets interrupted: arm the
+    // timer before handing control to the first task, or preemption never
+    // starts.
+    trap::enable_timer_interrupt();
+    timer::set_next_trigger();
+    task::add_initial_tasks();
+    task::run_tasks();
+    panic!("Unreachable in rust_main!");
+}
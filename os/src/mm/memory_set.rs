@@ -0,0 +1,500 @@
+//! Implementation of [`MapArea`] and [`MemorySet`].
+
+use super::{frame_alloc, FrameTracker};
+use super::{PTEFlags, PageTable, PageTableEntry};
+use super::{PhysAddr, PhysPageNum, StepByOne, VPNRange, VirtAddr, VirtPageNum};
+use crate::config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
+use crate::sync::UPSafeCell;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bitflags::*;
+use lazy_static::*;
+use riscv::register::satp;
+
+extern "C" {
+    fn stext();
+    fn etext();
+    fn srodata();
+    fn erodata();
+    fn sdata();
+    fn edata();
+    fn sbss_with_stack();
+    fn ebss();
+    fn ekernel();
+    fn strampoline();
+}
+
+lazy_static! {
+    /// The kernel's own memory space, shared by every task while it is
+    /// running in supervisor mode.
+    pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> =
+        Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
+}
+
+/// How a [`MapArea`]'s virtual pages are backed by physical frames
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum MapType {
+    /// Physical page number equals virtual page number (kernel `.text` etc.)
+    Identical,
+    /// Each virtual page gets its own allocated frame
+    Framed,
+}
+
+bitflags! {
+    /// Map permission, matching the R/W/X/U bits of a page table entry
+    pub struct MapPermission: u8 {
+        const R = 1 << 1;
+        const W = 1 << 2;
+        const X = 1 << 3;
+        const U = 1 << 4;
+    }
+}
+
+/// A contiguous range of virtual pages mapped with the same type and
+/// permission.
+///
+/// `data_frames` only holds an entry for a page once it has actually been
+/// allocated: [`MemorySet::insert_lazy_area`] pushes an area whose pages are
+/// not yet present in `data_frames` or the page table at all, and
+/// [`MapArea::map_one`] fills one in on demand.
+pub struct MapArea {
+    vpn_range: VPNRange,
+    data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    map_type: MapType,
+    map_perm: MapPermission,
+}
+
+impl MapArea {
+    pub fn new(
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        map_type: MapType,
+        map_perm: MapPermission,
+    ) -> Self {
+        let start_vpn: VirtPageNum = start_va.floor();
+        let end_vpn: VirtPageNum = end_va.ceil();
+        Self {
+            vpn_range: VPNRange::new(start_vpn, end_vpn),
+            data_frames: BTreeMap::new(),
+            map_type,
+            map_perm,
+        }
+    }
+
+    pub fn vpn_range(&self) -> VPNRange {
+        self.vpn_range
+    }
+
+    pub fn map_perm(&self) -> MapPermission {
+        self.map_perm
+    }
+
+    /// Whether `vpn` falls within this area's range at all, regardless of
+    /// whether the page has actually been faulted in yet.
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        vpn >= self.vpn_range.get_start() && vpn < self.vpn_range.get_end()
+    }
+
+    /// Allocate (if framed) and map a single page of this area.
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
+        let ppn: PhysPageNum;
+        match self.map_type {
+            MapType::Identical => {
+                ppn = PhysPageNum(vpn.0);
+            }
+            MapType::Framed => {
+                let frame = frame_alloc().unwrap();
+                ppn = frame.ppn;
+                self.data_frames.insert(vpn, frame);
+            }
+        }
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits()).unwrap();
+        page_table.map(vpn, ppn, pte_flags);
+    }
+
+    /// Unmap a single page of this area, if it was ever actually mapped.
+    /// A page recorded by `insert_lazy_area` but never touched has no page
+    /// table entry and no frame to give back, so this is then a no-op.
+    pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
+        if self.map_type == MapType::Framed {
+            self.data_frames.remove(&vpn);
+        }
+        if page_table
+            .translate(vpn)
+            .map_or(false, |pte| pte.is_valid())
+        {
+            page_table.unmap(vpn);
+        }
+    }
+
+    pub fn map(&mut self, page_table: &mut PageTable) {
+        let vpn_range = self.vpn_range;
+        for vpn in vpn_range {
+            self.map_one(page_table, vpn);
+        }
+    }
+
+    pub fn unmap(&mut self, page_table: &mut PageTable) {
+        let vpn_range = self.vpn_range;
+        for vpn in vpn_range {
+            self.unmap_one(page_table, vpn);
+        }
+    }
+
+    /// Split `[new_start, new_end)` off of this area into a fresh one,
+    /// carrying over whichever of those pages already have a frame, so
+    /// `MemorySet::free_area` can carve a partially-unmapped range out of
+    /// an area without losing the part that's kept.
+    fn carve(&mut self, new_start: VirtPageNum, new_end: VirtPageNum) -> MapArea {
+        let mut data_frames = BTreeMap::new();
+        let mut vpn = new_start;
+        while vpn < new_end {
+            if let Some(frame) = self.data_frames.remove(&vpn) {
+                data_frames.insert(vpn, frame);
+            }
+            vpn.step();
+        }
+        MapArea {
+            vpn_range: VPNRange::new(new_start, new_end),
+            data_frames,
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+        }
+    }
+
+    /// Copy `data` in at the start of this area, page by page. Only valid
+    /// for a `Framed` area that has already been mapped in full.
+    pub fn copy_data(&mut self, page_table: &PageTable, data: &[u8]) {
+        assert_eq!(self.map_type, MapType::Framed);
+        let mut start: usize = 0;
+        let mut current_vpn = self.vpn_range.get_start();
+        let len = data.len();
+        loop {
+            let src = &data[start..len.min(start + PAGE_SIZE)];
+            let dst = &mut page_table
+                .translate(current_vpn)
+                .unwrap()
+                .ppn()
+                .get_bytes_array()[..src.len()];
+            dst.copy_from_slice(src);
+            start += PAGE_SIZE;
+            if start >= len {
+                break;
+            }
+            current_vpn.step();
+        }
+    }
+}
+
+/// An address space: a page table plus the list of areas mapped into it.
+pub struct MemorySet {
+    page_table: PageTable,
+    areas: Vec<MapArea>,
+}
+
+impl MemorySet {
+    pub fn new_bare() -> Self {
+        Self {
+            page_table: PageTable::new(),
+            areas: Vec::new(),
+        }
+    }
+
+    /// Map a brand new area, eagerly allocating every page up front.
+    pub fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
+        map_area.map(&mut self.page_table);
+        if let Some(data) = data {
+            map_area.copy_data(&self.page_table, data);
+        }
+        self.areas.push(map_area);
+    }
+
+    /// Record `[start_va, end_va)` as mapped with `perm`, allocating every
+    /// page in the range right away.
+    pub fn insert_framed_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        perm: MapPermission,
+    ) {
+        self.push(
+            MapArea::new(start_va, end_va, MapType::Framed, perm),
+            None,
+        );
+    }
+
+    /// Record `[start_va, end_va)` as reserved for `perm`, but don't
+    /// allocate or map a single page of it yet.
+    ///
+    /// The area still shows up in `areas` (so [`Self::include_allocated`]
+    /// treats the range as taken and a later [`Self::free_area`] can find
+    /// and drop it), but every page in it stays absent from the page table
+    /// until [`Self::try_lazy_alloc`] backs it on first touch.
+    pub fn insert_lazy_area(&mut self, start_va: VirtAddr, end_va: VirtAddr, perm: MapPermission) {
+        self.areas
+            .push(MapArea::new(start_va, end_va, MapType::Framed, perm));
+    }
+
+    /// Back the single page containing `va` with a freshly allocated frame,
+    /// if `va` falls inside an area that permits `required_perm` but hasn't
+    /// been faulted in yet. Returns whether it found and backed such a page.
+    pub fn try_lazy_alloc(&mut self, va: VirtAddr, required_perm: MapPermission) -> bool {
+        let vpn = va.floor();
+        let area = match self
+            .areas
+            .iter_mut()
+            .find(|area| area.contains(vpn) && area.map_perm.contains(required_perm))
+        {
+            Some(area) => area,
+            None => return false,
+        };
+        if self.page_table.translate(vpn).map_or(false, |pte| pte.is_valid()) {
+            return false;
+        }
+        area.map_one(&mut self.page_table, vpn);
+        true
+    }
+
+    /// Whether any existing area overlaps `[start_va, end_va)`, mapped or
+    /// still pending.
+    pub fn include_allocated(&self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        let start_vpn = start_va.floor();
+        let end_vpn = end_va.ceil();
+        self.areas.iter().any(|area| {
+            area.vpn_range.get_start() < end_vpn && start_vpn < area.vpn_range.get_end()
+        })
+    }
+
+    /// Unmap exactly `[start_va, end_va)`, whether that means dropping whole
+    /// areas or carving the mapped/pending range out of ones it only
+    /// partially covers. Returns `false`, leaving every area untouched, if
+    /// any page in the range isn't currently reserved by some area (mapped
+    /// or still lazily pending) at all.
+    pub fn free_area(&mut self, start_va: VirtAddr, end_va: VirtAddr) -> bool {
+        let start_vpn = start_va.floor();
+        let end_vpn = end_va.ceil();
+
+        let mut vpn = start_vpn;
+        while vpn < end_vpn {
+            if !self.areas.iter().any(|area| area.contains(vpn)) {
+                return false;
+            }
+            vpn.step();
+        }
+
+        let page_table = &mut self.page_table;
+        let mut remainders = Vec::new();
+        self.areas.retain_mut(|area| {
+            let area_start = area.vpn_range.get_start();
+            let area_end = area.vpn_range.get_end();
+            if area_end <= start_vpn || area_start >= end_vpn {
+                return true;
+            }
+            let cut_start = area_start.max(start_vpn);
+            let cut_end = area_end.min(end_vpn);
+            let mut vpn = cut_start;
+            while vpn < cut_end {
+                area.unmap_one(page_table, vpn);
+                vpn.step();
+            }
+            if area_start < start_vpn {
+                remainders.push(area.carve(area_start, start_vpn));
+            }
+            if area_end > end_vpn {
+                remainders.push(area.carve(end_vpn, area_end));
+            }
+            false
+        });
+        self.areas.extend(remainders);
+        true
+    }
+
+    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
+        if let Some((idx, area)) = self
+            .areas
+            .iter_mut()
+            .enumerate()
+            .find(|(_, area)| area.vpn_range.get_start() == start_vpn)
+        {
+            area.unmap(&mut self.page_table);
+            self.areas.remove(idx);
+        }
+    }
+
+    fn map_trampoline(&mut self) {
+        self.page_table.map(
+            VirtAddr::from(TRAMPOLINE).into(),
+            PhysAddr::from(strampoline as usize).into(),
+            PTEFlags::R | PTEFlags::X,
+        );
+    }
+
+    /// Map the kernel's own address space: identity-mapped `.text`,
+    /// `.rodata`, `.data`, `.bss` and the remaining physical memory, plus
+    /// the trampoline page.
+    pub fn new_kernel() -> Self {
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        memory_set.push(
+            MapArea::new(
+                (stext as usize).into(),
+                (etext as usize).into(),
+                MapType::Identical,
+                MapPermission::R | MapPermission::X,
+            ),
+            None,
+        );
+        memory_set.push(
+            MapArea::new(
+                (srodata as usize).into(),
+                (erodata as usize).into(),
+                MapType::Identical,
+                MapPermission::R,
+            ),
+            None,
+        );
+        memory_set.push(
+            MapArea::new(
+                (sdata as usize).into(),
+                (edata as usize).into(),
+                MapType::Identical,
+                MapPermission::R | MapPermission::W,
+            ),
+            None,
+        );
+        memory_set.push(
+            MapArea::new(
+                (sbss_with_stack as usize).into(),
+                (ebss as usize).into(),
+                MapType::Identical,
+                MapPermission::R | MapPermission::W,
+            ),
+            None,
+        );
+        memory_set.push(
+            MapArea::new(
+                (ekernel as usize).into(),
+                MEMORY_END.into(),
+                MapType::Identical,
+                MapPermission::R | MapPermission::W,
+            ),
+            None,
+        );
+        memory_set
+    }
+
+    /// Build a fresh user address space from an ELF image, returning it
+    /// along with the initial user stack pointer and the entry point.
+    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf_header = elf.header;
+        let magic = elf_header.pt1.magic;
+        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        let ph_count = elf_header.pt2.ph_count();
+        let mut max_end_vpn = VirtPageNum(0);
+        for i in 0..ph_count {
+            let ph = elf.program_header(i).unwrap();
+            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+                let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
+                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
+                let mut map_perm = MapPermission::U;
+                let ph_flags = ph.flags();
+                if ph_flags.is_read() {
+                    map_perm |= MapPermission::R;
+                }
+                if ph_flags.is_write() {
+                    map_perm |= MapPermission::W;
+                }
+                if ph_flags.is_execute() {
+                    map_perm |= MapPermission::X;
+                }
+                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+                max_end_vpn = map_area.vpn_range.get_end();
+                memory_set.push(
+                    map_area,
+                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
+                );
+            }
+        }
+        let max_end_va: VirtAddr = max_end_vpn.into();
+        let mut user_stack_bottom: usize = max_end_va.into();
+        user_stack_bottom += PAGE_SIZE;
+        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        memory_set.push(
+            MapArea::new(
+                user_stack_bottom.into(),
+                user_stack_top.into(),
+                MapType::Framed,
+                MapPermission::R | MapPermission::W | MapPermission::U,
+            ),
+            None,
+        );
+        memory_set.push(
+            MapArea::new(
+                TRAP_CONTEXT.into(),
+                TRAMPOLINE.into(),
+                MapType::Framed,
+                MapPermission::R | MapPermission::W,
+            ),
+            None,
+        );
+        (
+            memory_set,
+            user_stack_top,
+            elf.header.pt2.entry_point() as usize,
+        )
+    }
+
+    /// Copy an existing user address space into a brand new one, allocating
+    /// a fresh frame for, and copying, every page the source has actually
+    /// mapped. Pages only reserved by [`Self::insert_lazy_area`] and never
+    /// touched stay reserved but unbacked in the copy.
+    pub fn from_existing_user(user_space: &Self) -> Self {
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        for area in user_space.areas.iter() {
+            let mut new_area = MapArea::new(
+                area.vpn_range.get_start().into(),
+                area.vpn_range.get_end().into(),
+                area.map_type,
+                area.map_perm,
+            );
+            // Only back the pages the parent has actually faulted in. A page
+            // still lazily pending (see `insert_lazy_area`) has no PTE here
+            // either, so a fork doesn't commit a whole unmapped mmap region
+            // to real frames just because the parent reserved it.
+            for vpn in area.vpn_range {
+                if let Some(src_pte) = user_space.translate(vpn) {
+                    if src_pte.is_valid() {
+                        new_area.map_one(&mut memory_set.page_table, vpn);
+                        let dst_ppn = memory_set.translate(vpn).unwrap().ppn();
+                        dst_ppn
+                            .get_bytes_array()
+                            .copy_from_slice(src_pte.ppn().get_bytes_array());
+                    }
+                }
+            }
+            memory_set.areas.push(new_area);
+        }
+        memory_set
+    }
+
+    pub fn token(&self) -> usize {
+        self.page_table.token()
+    }
+
+    pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
+        self.page_table.translate(vpn)
+    }
+
+    pub fn activate(&self) {
+        let satp = self.page_table.token();
+        unsafe {
+            satp::write(satp);
+            core::arch::asm!("sfence.vma");
+        }
+    }
+}
@@ -16,6 +16,14 @@ const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
 /// Yield syscall identifier
 const SYSCALL_YIELD: usize = 124;
+/// Get pid syscall identifier
+const SYSCALL_GETPID: usize = 172;
+/// Fork syscall identifier
+const SYSCALL_FORK: usize = 220;
+/// Exec syscall identifier
+const SYSCALL_EXEC: usize = 221;
+/// Waitpid syscall identifier
+const SYSCALL_WAITPID: usize = 260;
 /// Get time syscall identifier
 const SYSCALL_GET_TIME: usize = 169;
 /// Task info syscall identifier
@@ -46,6 +54,10 @@ pub fn syscall(syscall_id: usize, args: [usize; 3]) -> Result<isize, &'static st
             let task_info_ptr = args[0] as *mut TaskInfo;
             sys_task_info(task_info_ptr).map(|_| 0)
         },
+        SYSCALL_GETPID => Ok(sys_getpid()),
+        SYSCALL_FORK => Ok(sys_fork()),
+        SYSCALL_EXEC => Ok(sys_exec(args[0] as *const u8)),
+        SYSCALL_WAITPID => Ok(sys_waitpid(args[0] as isize, args[1] as *mut i32)),
         _ => Err("Unsupported syscall_id"),  // Return an error instead of panicking
     }
 }
@@ -1,13 +1,15 @@
 //! Process management syscalls
 use crate::{
     config::MAX_SYSCALL_NUM,
+    loader::get_app_data_by_name,
+    mm::{translated_refmut, translated_str},
     task::{
-        exit_current_and_run_next, get_syscall_times, get_task_time, suspend_current_and_run_next,
-        TaskStatus,
+        add_task, current_task, current_task_info, current_user_token,
+        exit_current_and_run_next, suspend_current_and_run_next, TaskStatus,
     },
     timer::get_time_us,
 };
-use crate::task::TaskStatus::Running;
+use alloc::sync::Arc;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -16,30 +18,34 @@ pub struct TimeVal {
     pub usec: usize,
 }
 
-/// Task information
+/// Task information, as reported by `sys_task_info`
 #[allow(dead_code)]
 pub struct TaskInfo {
     /// Task status in it's life cycle
-    status: TaskStatus,// 任务状态
+    pub(crate) status: TaskStatus,// 任务状态
     /// The numbers of syscall called by task
-    syscall_times: [u32; MAX_SYSCALL_NUM],// 记录每个系统调用的调用次数
+    pub(crate) syscall_times: [u32; MAX_SYSCALL_NUM],// 记录每个系统调用的调用次数
     /// Total running time of task
-    time: usize,// 任务的总运行时间
+    pub(crate) time: usize,// 任务的总运行时间
 }
 impl TaskInfo {
-    pub fn modify_task_info(task_info:*mut Self)->Option<()>{
-        unsafe{
-            (*task_info).status=Running; // 设置任务状态为 Running
-            (*task_info).syscall_times=get_syscall_times();// 获取系统调用次数
-            (*task_info).time=get_task_time();// 获取任务的总运行时间
-        }
-        Some(())// 成功修改后返回 Some
+    /// `task_info` is a user-space pointer: this kernel runs on its own
+    /// (kernel) page table, so the write has to go through the current
+    /// task's `MemorySet`, the same way `sys_waitpid` writes `exit_code_ptr`
+    /// back with `translated_refmut`, not a raw dereference.
+    pub fn modify_task_info(task_info: *mut Self) -> Option<()> {
+        let info = current_task_info();
+        let ti = translated_refmut(current_user_token(), task_info);
+        ti.status = info.status;
+        ti.syscall_times = info.syscall_times;
+        ti.time = info.time;
+        Some(())
     }
 }
 /// task exits and submit an exit code
 pub fn sys_exit(exit_code: i32) -> ! {
     trace!("[kernel] Application exited with code {}", exit_code);
-    exit_current_and_run_next();
+    exit_current_and_run_next(exit_code);
     panic!("Unreachable in sys_exit!");
 }
 
@@ -71,3 +77,69 @@ pub fn sys_task_info(ti: *mut TaskInfo) -> isize {
         Some(_) => 0// 成功填充任务信息，返回 0
     }
 }
+
+/// Get the pid of the current task
+pub fn sys_getpid() -> isize {
+    trace!("kernel: sys_getpid");
+    current_task().unwrap().pid.0 as isize
+}
+
+/// Clone the current task's address space into a new child task.
+///
+/// Returns the child's pid to the parent; the child itself sees a return
+/// value of 0, set directly in its copied trap context by `fork`.
+pub fn sys_fork() -> isize {
+    trace!("kernel: sys_fork");
+    let current_task = current_task().unwrap();
+    let new_task = current_task.fork();
+    let new_pid = new_task.getpid();
+    add_task(new_task);
+    new_pid as isize
+}
+
+/// Replace the current task's address space with the named app image.
+pub fn sys_exec(path: *const u8) -> isize {
+    trace!("kernel: sys_exec");
+    let token = current_user_token();
+    let path = translated_str(token, path);
+    if let Some(data) = get_app_data_by_name(path.as_str()) {
+        let task = current_task().unwrap();
+        task.exec(data);
+        0
+    } else {
+        -1
+    }
+}
+
+/// Wait for a child to exit.
+///
+/// `pid == -1` matches any child. Returns -1 if no such child exists, -2 if
+/// it exists but hasn't exited yet, or the child's pid once it has been
+/// reaped and its exit code written through `exit_code_ptr`.
+pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
+    trace!("kernel: sys_waitpid");
+    let task = current_task().unwrap();
+
+    let mut inner = task.inner_exclusive_access();
+    if !inner
+        .children
+        .iter()
+        .any(|p| pid == -1 || pid as usize == p.getpid())
+    {
+        return -1;
+    }
+    let pair = inner.children.iter().enumerate().find(|(_, p)| {
+        let p_inner = p.inner_exclusive_access();
+        p_inner.is_zombie() && (pid == -1 || pid as usize == p.getpid())
+    });
+    if let Some((idx, _)) = pair {
+        let child = inner.children.remove(idx);
+        assert_eq!(Arc::strong_count(&child), 1);
+        let found_pid = child.getpid();
+        let exit_code = child.inner_exclusive_access().exit_code;
+        *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
+        found_pid as isize
+    } else {
+        -2
+    }
+}
@@ -1,243 +1,177 @@
 //! Task management implementation
 //!
-//! Everything about task management, like starting and switching tasks is
+//! Everything about task management, like starting and switching tasks, is
 //! implemented here.
 //!
-//! A single global instance of [`TaskManager`] called `TASK_MANAGER` controls
-//! all the tasks in the operating system.
+//! A single global instance of [`TaskManager`] called `TASK_MANAGER` owns the
+//! ready queue of every task that is runnable but not currently on the CPU.
+//! "What is running right now" instead lives in [`processor::Processor`],
+//! which drives `__switch` between the idle control flow and whichever task
+//! `fetch_task` hands it.
 //!
 //! Be careful when you see `__switch` ASM function in `switch.S`. Control flow around this function
 //! might not be what you expect.
 
 mod context;
+mod pid;
+mod processor;
 mod switch;
 #[allow(clippy::module_inception)]
 mod task;
 
-use crate::config::{MAX_APP_NUM, MAX_SYSCALL_NUM};
-use crate::loader::{get_num_app, init_app_cx};
+use crate::loader::{get_app_data, get_num_app};
 use crate::sync::UPSafeCell;
-use crate::timer::get_time_ms;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
 use lazy_static::*;
 use switch::__switch;
-pub use task::{TaskControlBlock, TaskStatus};
+pub use task::{TaskControlBlock, TaskControlBlockInner, TaskStatus};
 
 pub use context::TaskContext;
+pub use processor::{
+    current_task, current_task_info, current_trap_cx, current_user_token, run_tasks, schedule,
+    set_priority, take_current_task,
+};
 
-/// The task manager, where all the tasks are managed.
+/// The task manager, holding every `Ready` task that is not currently
+/// running.
 ///
-/// Functions implemented on `TaskManager` deals with all task state transitions
-/// and task context switching. For convenience, you can find wrappers around it
-/// in the module level.
-///
-/// Most of `TaskManager` are hidden behind the field `inner`, to defer
-/// borrowing checks to runtime. You can see examples on how to use `inner` in
-/// existing functions on `TaskManager`.
+/// Tasks are handed out and returned as `Arc<TaskControlBlock>`, so there is
+/// no `MAX_APP_NUM`-sized array to size up front and no fixed slot a task is
+/// tied to.
 pub struct TaskManager {
-    /// total number of tasks
-    num_app: usize,
-    /// use inner value to get mutable access
-    inner: UPSafeCell<TaskManagerInner>,
-}
-
-/// Inner of Task Manager
-pub struct TaskManagerInner {
-    /// task list
-    tasks: [TaskControlBlock; MAX_APP_NUM],
-    /// id of current `Running` task
-    current_task: usize,
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
 }
 
-lazy_static! {
-    pub static ref TASK_MANAGER: TaskManager = {
-        let num_app = get_num_app();
-        let mut tasks = [TaskControlBlock {
-            task_cx: TaskContext::zero_init(),
-            task_status: TaskStatus::UnInit,
-            task_syscall_times: [0; MAX_SYSCALL_NUM],
-            syscall_timestamps: [0; MAX_SYSCALL_NUM], // 初始化为 0
-            task_time: 0,
-            first_scheduled_time: None, // 初始化为 None
-        }; MAX_APP_NUM];
-        for (i, task) in tasks.iter_mut().enumerate() {
-            task.task_cx = TaskContext::goto_restore(init_app_cx(i));
-            task.task_status = TaskStatus::Ready;
-        }
-        TaskManager {
-            num_app,
-            inner: unsafe {
-                UPSafeCell::new(TaskManagerInner {
-                    tasks,
-                    current_task: 0,
-                })
-            },
-        }
-    };
-}
 impl TaskManager {
-    /// Run the first task in task list.
-    ///
-    /// Generally, the first task in task list is an idle task (we call it zero process later).
-    /// But in ch3, we load apps statically, so the first task is a real app.
-    fn run_first_task(&self) -> ! {
-        let mut inner = self.inner.exclusive_access();
-        let task0 = &mut inner.tasks[0];
-        task0.task_status = TaskStatus::Running;
-        task0.task_time = get_time_ms();
-        
-        // 如果是第一次被调度，记录第一次调度的时间
-        if task0.first_scheduled_time.is_none() {
-            task0.first_scheduled_time = Some(get_time_ms());
+    /// Create an empty ready queue
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
         }
-    
-        let next_task_cx_ptr = &task0.task_cx as *const TaskContext;
-        drop(inner);
-        let mut _unused = TaskContext::zero_init();
-        
-        unsafe {
-            __switch(&mut _unused as *mut TaskContext, next_task_cx_ptr);
-        }
-        panic!("unreachable in run_first_task!");
-    }    
-
-    /// Change the status of current `Running` task into `Ready`.
-    fn mark_current_suspended(&self) {
-        let mut inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        inner.tasks[current].task_status = TaskStatus::Ready;
     }
 
-    /// Change the status of current `Running` task into `Exited`.
-    fn mark_current_exited(&self) {
-        let mut inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        inner.tasks[current].task_status = TaskStatus::Exited;
+    /// Put a task at the back of the ready queue
+    pub fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
     }
 
-    /// Find next task to run and return task id.
+    /// Take the ready task with the smallest stride, advancing its stride
+    /// by its pass value (`BIG_STRIDE / priority`) before handing it out.
     ///
-    /// In this case, we only return the first `Ready` task in task list.
-    fn find_next_task(&self) -> Option<usize> {
-        let inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        (current + 1..current + self.num_app + 1)
-            .map(|id| id % self.num_app)
-            .find(|id| inner.tasks[*id].task_status == TaskStatus::Ready)
-    }
-
-    /// Switch current `Running` task to the task we have found,
-    /// or there is no `Ready` task and we can exit with all applications completed
-    fn run_next_task(&self) {
-        if let Some(next) = self.find_next_task() {
-            let mut inner = self.inner.exclusive_access();
-            let current = inner.current_task;
-            inner.tasks[next].task_status = TaskStatus::Running;
-            inner.tasks[current].task_time = get_time_ms() - inner.tasks[current].task_time;
-            inner.tasks[next].task_time = get_time_ms();
-            
-            // 如果是第一次被调度，记录第一次调度的时间
-            if inner.tasks[next].first_scheduled_time.is_none() {
-                inner.tasks[next].first_scheduled_time = Some(get_time_ms());
-            }
-    
-            inner.current_task = next;
-            let current_task_cx_ptr = &mut inner.tasks[current].task_cx as *mut TaskContext;
-            let next_task_cx_ptr = &inner.tasks[next].task_cx as *const TaskContext;
-            drop(inner);
-            
-            unsafe {
-                __switch(current_task_cx_ptr, next_task_cx_ptr);
+    /// Strides are compared via wrapping subtraction, cast to `isize`: with
+    /// every priority kept `>= 2` (enforced by `set_priority`), the largest
+    /// gap between any two strides stays within `BIG_STRIDE / 2`, so this
+    /// ordering is correct even once `stride` wraps around.
+    pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let mut min_idx = None;
+        let mut min_stride = 0usize;
+        for (idx, task) in self.ready_queue.iter().enumerate() {
+            let stride = task.inner_exclusive_access().stride;
+            if min_idx.is_none() || (stride.wrapping_sub(min_stride) as isize) < 0 {
+                min_idx = Some(idx);
+                min_stride = stride;
             }
-        } else {
-            panic!("All applications completed!");
         }
-    }
-    
-    fn get_syscall_times(&self) -> [u32; MAX_SYSCALL_NUM] {
-        let inner = self.inner.exclusive_access();
-        inner.tasks[inner.current_task].task_syscall_times
-    }
-
-    fn get_current_task_time(&self) -> usize {
-        let inner = self.inner.exclusive_access();
-        inner.tasks[inner.current_task].task_time
-    }
-
-    fn update_syscall_times(&self, syscall_id: usize) {
-        let mut inner = self.inner.exclusive_access();
-        let current = inner.current_task;
-        inner.tasks[current].task_syscall_times[syscall_id] += 1;
-        
-        // 记录系统调用的时间戳
-        inner.tasks[current].syscall_timestamps[syscall_id] = get_time_ms();
-    }
-    /// 获取当前任务的系统调用时间戳
-    fn get_syscall_timestamps(&self) -> [usize; MAX_SYSCALL_NUM] {
-        let inner = self.inner.exclusive_access();
-        inner.tasks[inner.current_task].syscall_timestamps
-    }
-
-    /// 获取当前任务第一次调度的时间
-    fn get_first_scheduled_time(&self) -> Option<usize> {
-        let inner = self.inner.exclusive_access();
-        inner.tasks[inner.current_task].first_scheduled_time
+        let task = self.ready_queue.remove(min_idx?)?;
+        let mut inner = task.inner_exclusive_access();
+        let pass = BIG_STRIDE / (inner.priority as usize);
+        inner.stride = inner.stride.wrapping_add(pass);
+        drop(inner);
+        Some(task)
     }
 }
 
-/// 获取当前任务的系统调用时间戳
-pub fn get_syscall_timestamps() -> [usize; MAX_SYSCALL_NUM] {
-    TASK_MANAGER.get_syscall_timestamps()
-}
+/// Large constant stride budget; each task's `pass = BIG_STRIDE / priority`
+/// determines how fast its stride advances every time it is scheduled.
+const BIG_STRIDE: usize = 0xFFFF_FFFF;
 
-/// 获取当前任务第一次被调度的时间
-pub fn get_first_scheduled_time() -> Option<usize> {
-    TASK_MANAGER.get_first_scheduled_time()
+lazy_static! {
+    /// Global ready queue, shared by every task
+    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> =
+        unsafe { UPSafeCell::new(TaskManager::new()) };
 }
 
-/// Run the first task in task list.
-pub fn run_first_task() {
-    TASK_MANAGER.run_first_task();
-}
+pub use pid::{pid_alloc, KernelStack, PidHandle};
 
-/// Switch current `Running` task to the task we have found,
-/// or there is no `Ready` task and we can exit with all applications completed
-fn run_next_task() {
-    TASK_MANAGER.run_next_task();
+/// Put a task at the back of the ready queue
+pub fn add_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.exclusive_access().add(task);
 }
 
-/// Change the status of current `Running` task into `Ready`.
-fn mark_current_suspended() {
-    TASK_MANAGER.mark_current_suspended();
+/// Take the ready task with the smallest stride, if any (see [`TaskManager::fetch`])
+pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
+    TASK_MANAGER.exclusive_access().fetch()
 }
 
-/// Change the status of current `Running` task into `Exited`.
-fn mark_current_exited() {
-    TASK_MANAGER.mark_current_exited();
+/// Load every statically linked app as a `Ready` task and place it on the
+/// ready queue. Called once, before `processor::run_tasks` starts driving
+/// the idle control flow.
+pub fn add_initial_tasks() {
+    let num_app = get_num_app();
+    for app_id in 0..num_app {
+        add_task(Arc::new(TaskControlBlock::new(get_app_data(app_id))));
+    }
 }
 
-/// Suspend the current 'Running' task and run the next task in task list.
+/// Suspend the current `Running` task, put it back on the ready queue, and
+/// switch to idle control flow so the next task can be picked.
+///
+/// This fires for both voluntary yields and timer-driven preemption, so the
+/// slice just spent running is folded into `task_time` here rather than in
+/// the (voluntary-only) `sys_yield` path, keeping CPU accounting correct
+/// whether or not a task ever yields on its own.
 pub fn suspend_current_and_run_next() {
-    mark_current_suspended();
-    run_next_task();
-}
-
-/// Exit the current 'Running' task and run the next task in task list.
-pub fn exit_current_and_run_next() {
-    mark_current_exited();
-    run_next_task();
+    let task = take_current_task().unwrap();
+    let task_cx_ptr = {
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.task_status = TaskStatus::Ready;
+        task_inner.task_time += crate::timer::get_time_ms()
+            .saturating_sub(task_inner.slice_started_at);
+        &mut task_inner.task_cx as *mut TaskContext
+    };
+    add_task(task);
+    schedule(task_cx_ptr);
 }
 
-/// Get the syscall times of current task.
-pub fn get_syscall_times() -> [u32; MAX_SYSCALL_NUM] {
-    TASK_MANAGER.get_syscall_times()
+/// Exit the current `Running` task with `exit_code` and switch to idle
+/// control flow so the next task can be picked.
+///
+/// The task itself is not dropped: it becomes a zombie so its parent can
+/// `waitpid` on it and read `exit_code` back. Its own children are orphaned
+/// rather than reparented, since nothing in this kernel plays the role of an
+/// init process yet; whoever already holds an `Arc` to them keeps them
+/// running, but no one will ever reap them.
+pub fn exit_current_and_run_next(exit_code: i32) {
+    let task = take_current_task().unwrap();
+    {
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.task_status = TaskStatus::Exited;
+        task_inner.exit_code = exit_code;
+        task_inner.children.clear();
+    }
+    drop(task);
+    let mut _unused = TaskContext::zero_init();
+    schedule(&mut _unused as *mut TaskContext);
 }
 
-/// Get the total running time of current task.
-pub fn get_current_task_time() -> usize {
-    TASK_MANAGER.get_current_task_time()
+/// Get the syscall counts of the current task.
+pub fn get_syscall_times() -> [u32; crate::config::MAX_SYSCALL_NUM] {
+    current_task().unwrap().inner_exclusive_access().task_syscall_times
 }
 
-/// Update the syscall times of current task.
+/// Record an invocation of `syscall_id` against the current task.
+///
+/// This is the single path that feeds the per-syscall counts `sys_task_info`
+/// reports through [`processor::current_task_info`]: every syscall goes
+/// through here, and nowhere else touches `task_syscall_times`.
 pub fn update_syscall_times(syscall_id: usize) {
-    TASK_MANAGER.update_syscall_times(syscall_id);
-}
\ No newline at end of file
+    let task = current_task().unwrap();
+    let mut inner = task.inner_exclusive_access();
+    // `syscall_id` is the raw `a7` an application put in a register, so an
+    // `ecall` with an id past the end of the table must not panic here;
+    // `syscall`'s own dispatch match already reports it as unsupported.
+    if syscall_id < crate::config::MAX_SYSCALL_NUM {
+        inner.task_syscall_times[syscall_id] += 1;
+    }
+}
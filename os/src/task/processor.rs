@@ -55,7 +55,11 @@ lazy_static! {
 }
 
 ///The main part of process execution and scheduling
-///Loop `fetch_task` to get the process that needs to run, and switch the process through `__switch`
+///
+/// Loop `fetch_task` to get the process that needs to run, stamp its
+/// scheduling bookkeeping, and switch to it through `__switch`. The task
+/// runs until it calls `sys_yield`/`sys_exit` or the timer interrupt fires
+/// and preempts it; either way control comes back here through `schedule`.
 pub fn run_tasks() {
     loop {
         let mut processor = PROCESSOR.exclusive_access();
@@ -65,6 +69,7 @@ pub fn run_tasks() {
             let mut task_inner = task.inner_exclusive_access();
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
             task_inner.task_status = TaskStatus::Running;
+            task_inner.slice_started_at = get_time_ms();
             // release coming task_inner manually
             drop(task_inner);
             // release coming task TCB manually
@@ -75,7 +80,12 @@ pub fn run_tasks() {
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
         } else {
-            warn!("no tasks available in run_tasks");
+            // Nothing ready right now; wait for the next interrupt (e.g. the
+            // timer) instead of spinning this loop and flooding the log.
+            drop(processor);
+            unsafe {
+                riscv::asm::wfi();
+            }
         }
     }
 }
@@ -104,23 +114,31 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
         .get_trap_cx()
 }
 
-/// Get current task info
+/// Get current task info: real status, the full per-syscall count array,
+/// and total CPU time actually spent running, including the slice in
+/// progress right now.
 pub fn current_task_info() -> TaskInfo {
     let current_task_control_block = current_task().unwrap();
-    let current_task = current_task_control_block.inner.exclusive_access();
+    let current_task = current_task_control_block.inner_exclusive_access();
+
+    let cpu_time = current_task.task_time
+        + get_time_ms().saturating_sub(current_task.slice_started_at);
 
     TaskInfo {
         status: current_task.task_status,
-        syscall_times: current_task.task_syscall_trace,
-        time: {
-            let start = current_task.task_start_time;
-            let end = current_task.task_lastest_syscall_time;
-            end - start
-        },
+        syscall_times: current_task.task_syscall_times,
+        time: cpu_time,
     }
 }
 
-/// Allocate memory
+/// Allocate memory, lazily.
+///
+/// This only records `[start, start+len)` as a pending mapping with the
+/// requested permissions; no physical frame is committed until the task
+/// actually touches a page in the range and faults, at which point
+/// `trap_handler` maps that one page and resumes. This keeps a large,
+/// sparsely-touched mmap region from costing more than the pages it
+/// actually uses.
 pub fn allocate_memory(start: usize, len: usize, port: usize) -> isize {
     // check
     if start % PAGE_SIZE != 0 {
@@ -135,7 +153,7 @@ pub fn allocate_memory(start: usize, len: usize, port: usize) -> isize {
     let end_address = VirtAddr::from(start + len);
 
     let current_task_control_block = current_task().unwrap();
-    let mut current_task = current_task_control_block.inner.exclusive_access();
+    let mut current_task = current_task_control_block.inner_exclusive_access();
 
     if current_task
         .memory_set
@@ -148,12 +166,18 @@ pub fn allocate_memory(start: usize, len: usize, port: usize) -> isize {
 
     current_task
         .memory_set
-        .insert_framed_area(start_address, end_address, permissions);
+        .insert_lazy_area(start_address, end_address, permissions);
 
     0
 }
 
 /// Free memory
+///
+/// Unmaps exactly `[start, start+len)`, splitting any area that only
+/// partially overlaps it. Fails with `-1`, leaving every mapping untouched,
+/// if any page in the range isn't currently reserved (mapped or still
+/// lazily pending) at all — a page was never actually faulted in is still
+/// freed just as cleanly as one that was, but an unreserved page is not.
 pub fn free_memory(start: usize, len: usize) -> isize {
     if start % PAGE_SIZE != 0 {
         return -1;
@@ -171,24 +195,18 @@ pub fn free_memory(start: usize, len: usize) -> isize {
     }
 
     let current_task_control_block = current_task().unwrap();
-    let mut current_task = current_task_control_block.inner.exclusive_access();
+    let mut current_task = current_task_control_block.inner_exclusive_access();
 
-    current_task
+    if !current_task
         .memory_set
-        .free_framed_area(start_address, end_address);
+        .free_area(start_address, end_address)
+    {
+        return -1;
+    }
 
     0
 }
 
-/// Update task info
-pub fn update_task_info(syscall_id: usize) {
-    let current_task_control_block = current_task().unwrap();
-    let mut current_task = current_task_control_block.inner.exclusive_access();
-
-    current_task.task_lastest_syscall_time = get_time_ms();
-    current_task.task_syscall_trace[syscall_id] += 1;
-}
-
 ///Return to idle control flow for new scheduling
 pub fn schedule(switched_task_cx_ptr: *mut TaskContext) {
     let mut processor = PROCESSOR.exclusive_access();
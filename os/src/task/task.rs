@@ -1,58 +1,213 @@
+//! Types related to task management
+
+use super::pid::{pid_alloc, KernelStack, PidHandle};
 use super::TaskContext;
-use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::{MAX_SYSCALL_NUM, TRAP_CONTEXT};
+use crate::mm::{MapPermission, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
+use crate::sync::UPSafeCell;
+use crate::trap::{trap_handler, TrapContext};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::cell::RefMut;
 
 /// The task control block (TCB) of a task.
-#[derive(Copy, Clone)]
+///
+/// A task is always handed out as an `Arc<TaskControlBlock>` so the ready
+/// queue, the processor, a task's own trap handler and its parent/children
+/// can all hold a reference to the same task at once. Everything that can
+/// change while the task is alive lives behind `inner`, which defers borrow
+/// checking to runtime the same way the rest of this kernel does.
 pub struct TaskControlBlock {
+    /// Process id, stable for the whole life of the task
+    pub pid: PidHandle,
+    /// Kernel stack backing this task's trap/interrupt handling
+    kernel_stack: KernelStack,
+    /// Mutable inner state of the task
+    inner: UPSafeCell<TaskControlBlockInner>,
+}
+
+/// Mutable inner state of a [`TaskControlBlock`]
+pub struct TaskControlBlockInner {
+    /// The physical page number of the frame that holds the trap context
+    pub trap_cx_ppn: PhysPageNum,
+    /// Size of application + user stack, in bytes, already mapped
+    pub base_size: usize,
+    /// The task context used by `__switch`
+    pub task_cx: TaskContext,
     /// The task status in its lifecycle
     pub task_status: TaskStatus,
-    /// The task context
-    pub task_cx: TaskContext,
-    /// The task's syscall count (how many syscalls it has performed)
-    pub syscall_times: u32,
-    /// The current syscall (if any)
-    pub current_syscall: Option<u32>,
-    /// The time when the task was first scheduled (in milliseconds since epoch)
-    pub start_time: u64,
+    /// Address space of this task
+    pub memory_set: MemorySet,
+    /// The parent task, if any; weak so a parent dropping its last strong
+    /// reference to itself doesn't keep itself alive through its children
+    pub parent: Option<Weak<TaskControlBlock>>,
+    /// Child tasks, kept alive here until `waitpid` reaps them
+    pub children: Vec<Arc<TaskControlBlock>>,
+    /// Exit code, valid once `task_status` is `Exited`
+    pub exit_code: i32,
+    /// Number of times each syscall has been invoked by this task, fed by
+    /// the single `update_syscall_times` path in `syscall::syscall`
+    pub task_syscall_times: [u32; MAX_SYSCALL_NUM],
+    /// Total CPU time (in ms) this task has actually spent running, not
+    /// counting time spent preempted/suspended; `time` in
+    /// [`crate::task::current_task_info`] adds the in-progress slice to this
+    pub task_time: usize,
+    /// Timestamp (in ms) the current run began; used to fold the slice just
+    /// spent running into `task_time` on every suspend, voluntary or not,
+    /// and to account for the still-in-progress slice in `current_task_info`
+    pub slice_started_at: usize,
+    /// Stride-scheduling priority set through `set_priority`
+    pub priority: isize,
+    /// Accumulated stride; the ready task with the smallest value runs next
+    pub stride: usize,
+}
+
+impl TaskControlBlockInner {
+    /// Get the mutable reference to the trap context of this task
+    pub fn get_trap_cx(&self) -> &'static mut TrapContext {
+        self.trap_cx_ppn.get_mut()
+    }
+
+    /// Get the token of the page table this task is running on
+    pub fn get_user_token(&self) -> usize {
+        self.memory_set.token()
+    }
+
+    /// Whether this task is a zombie, i.e. has exited but not been reaped
+    pub fn is_zombie(&self) -> bool {
+        self.task_status == TaskStatus::Exited
+    }
 }
 
 impl TaskControlBlock {
-    /// Get the current time in milliseconds since UNIX_EPOCH
-    fn get_current_time_ms() -> u64 {
-        let start = SystemTime::now();
-        let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
-        since_the_epoch.as_millis() as u64
+    /// Exclusive access to the inner state
+    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
+        self.inner.exclusive_access()
+    }
+
+    /// Get the token of the page table this task is running on
+    pub fn get_user_token(&self) -> usize {
+        self.inner_exclusive_access().get_user_token()
+    }
+
+    /// This task's pid
+    pub fn getpid(&self) -> usize {
+        self.pid.0
     }
 
-    /// Initialize the task control block with default values
-    pub fn new(task_cx: TaskContext) -> Self {
-        TaskControlBlock {
-            task_status: TaskStatus::UnInit,
-            task_cx,
-            syscall_times: 0,
-            current_syscall: None,
-            start_time: Self::get_current_time_ms(), // Initialize the start time
-        }
+    /// Build a brand new task from an app's ELF image, with no parent.
+    ///
+    /// Used for the statically linked apps loaded at boot; `exec` loads its
+    /// new image through `MemorySet::from_elf` directly instead.
+    pub fn new(elf_data: &[u8]) -> Self {
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let pid_handle = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack_top = kernel_stack.get_top();
+        let task_control_block = Self {
+            pid: pid_handle,
+            kernel_stack,
+            inner: unsafe {
+                UPSafeCell::new(TaskControlBlockInner {
+                    trap_cx_ppn,
+                    base_size: user_sp,
+                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                    task_status: TaskStatus::Ready,
+                    memory_set,
+                    parent: None,
+                    children: Vec::new(),
+                    exit_code: 0,
+                    task_syscall_times: [0; MAX_SYSCALL_NUM],
+                    task_time: 0,
+                    slice_started_at: 0,
+                    priority: 16,
+                    stride: 0,
+                })
+            },
+        };
+        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
+        *trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.exclusive_access().token(),
+            kernel_stack_top,
+            trap_handler as usize,
+        );
+        task_control_block
     }
 
-    /// Update the syscall count and set the current syscall
-    pub fn record_syscall(&mut self, syscall_id: u32) {
-        self.syscall_times += 1;
-        self.current_syscall = Some(syscall_id);
+    /// Replace this task's address space, entry point and user stack with a
+    /// freshly loaded ELF image, keeping its pid and kernel stack.
+    pub fn exec(&self, elf_data: &[u8]) {
+        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+
+        let mut inner = self.inner_exclusive_access();
+        inner.memory_set = memory_set;
+        inner.trap_cx_ppn = trap_cx_ppn;
+        inner.base_size = user_sp;
+        *inner.get_trap_cx() = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.exclusive_access().token(),
+            self.kernel_stack.get_top(),
+            trap_handler as usize,
+        );
     }
 
-    /// Get the time since the task was first scheduled, in milliseconds
-    pub fn get_time_since_first_schedule(&self) -> u64 {
-        let current_time = Self::get_current_time_ms();
-        current_time - self.start_time
+    /// Clone this task's address space and open state into a brand new
+    /// child task, linked into the process tree as a child of `self`.
+    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+        let mut parent_inner = self.inner_exclusive_access();
+        let memory_set = MemorySet::from_existing_user(&parent_inner.memory_set);
+        let trap_cx_ppn = memory_set
+            .translate(VirtAddr::from(TRAP_CONTEXT).into())
+            .unwrap()
+            .ppn();
+        let pid_handle = pid_alloc();
+        let kernel_stack = KernelStack::new(&pid_handle);
+        let kernel_stack_top = kernel_stack.get_top();
+        let task_control_block = Arc::new(TaskControlBlock {
+            pid: pid_handle,
+            kernel_stack,
+            inner: unsafe {
+                UPSafeCell::new(TaskControlBlockInner {
+                    trap_cx_ppn,
+                    base_size: parent_inner.base_size,
+                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                    task_status: TaskStatus::Ready,
+                    memory_set,
+                    parent: Some(Arc::downgrade(self)),
+                    children: Vec::new(),
+                    exit_code: 0,
+                    task_syscall_times: [0; MAX_SYSCALL_NUM],
+                    task_time: 0,
+                    slice_started_at: 0,
+                    priority: 16,
+                    stride: 0,
+                })
+            },
+        });
+        parent_inner.children.push(Arc::clone(&task_control_block));
+        // The child's trap context is a copy of the parent's, except that
+        // `a0` (x[10]) carries the fork return value, 0, to the child.
+        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
+        trap_cx.kernel_sp = kernel_stack_top;
+        trap_cx.x[10] = 0;
+        task_control_block
     }
 }
 
 /// The status of a task
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskStatus {
-    /// uninitialized
-    UnInit,
     /// ready to run
     Ready,
     /// running
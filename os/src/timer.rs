@@ -0,0 +1,34 @@
+//! RISC-V timer-related functionality
+
+use crate::config::CLOCK_FREQ;
+use crate::sbi::set_timer;
+use riscv::register::time;
+
+/// Number of timer interrupts per second, i.e. the length of one
+/// preemptive time slice is `1 / TICKS_PER_SEC` of a second.
+const TICKS_PER_SEC: usize = 100;
+/// Milliseconds per second, used to convert `mtime` ticks
+const MSEC_PER_SEC: usize = 1000;
+/// Microseconds per second, used to convert `mtime` ticks
+const MICRO_PER_SEC: usize = 1_000_000;
+
+/// Read the `mtime` register
+pub fn get_time() -> usize {
+    time::read()
+}
+
+/// Get the current time, in milliseconds
+pub fn get_time_ms() -> usize {
+    time::read() / (CLOCK_FREQ / MSEC_PER_SEC)
+}
+
+/// Get the current time, in microseconds
+pub fn get_time_us() -> usize {
+    time::read() / (CLOCK_FREQ / MICRO_PER_SEC)
+}
+
+/// Program the next supervisor timer interrupt one time slice ahead of now,
+/// so every task is preempted before it can run for longer than that.
+pub fn set_next_trigger() {
+    set_timer(get_time() + CLOCK_FREQ / TICKS_PER_SEC);
+}
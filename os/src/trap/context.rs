@@ -0,0 +1,52 @@
+//! Trap context, the register file saved/restored around the user<->kernel
+//! boundary by the `__alltraps`/`__restore` trampoline code.
+
+use riscv::register::sstatus::{self, Sstatus, SPP};
+
+/// Trap context
+#[repr(C)]
+pub struct TrapContext {
+    /// general-purpose registers x0~x31
+    pub x: [usize; 32],
+    /// supervisor status register
+    pub sstatus: Sstatus,
+    /// supervisor exception program counter
+    pub sepc: usize,
+    /// token of the kernel address space (satp)
+    pub kernel_satp: usize,
+    /// kernel stack pointer of the task this context belongs to
+    pub kernel_sp: usize,
+    /// virtual address of `trap_handler`
+    pub trap_handler: usize,
+}
+
+impl TrapContext {
+    /// Set the user stack pointer (x2, sp)
+    pub fn set_sp(&mut self, sp: usize) {
+        self.x[2] = sp;
+    }
+
+    /// Build the trap context a freshly created task first returns into,
+    /// jumping to `entry` with stack `sp` and supervisor mode already
+    /// configured to drop to `User` on `sret`.
+    pub fn app_init_context(
+        entry: usize,
+        sp: usize,
+        kernel_satp: usize,
+        kernel_sp: usize,
+        trap_handler: usize,
+    ) -> Self {
+        let mut sstatus = sstatus::read();
+        sstatus.set_spp(SPP::User);
+        let mut cx = Self {
+            x: [0; 32],
+            sstatus,
+            sepc: entry,
+            kernel_satp,
+            kernel_sp,
+            trap_handler,
+        };
+        cx.set_sp(sp);
+        cx
+    }
+}
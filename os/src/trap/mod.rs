@@ -0,0 +1,162 @@
+//! Trap handling functionality
+//!
+//! All traps — syscalls, exceptions and interrupts alike — land here after
+//! the `__alltraps` assembly stub has saved the interrupted task's
+//! [`TrapContext`]. This is also where the supervisor timer interrupt is
+//! acknowledged and turned into a scheduling decision, so a user task can
+//! never hold the CPU past the end of its time slice.
+
+mod context;
+
+use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::mm::{MapPermission, VirtAddr};
+use crate::syscall::syscall;
+use crate::task::{
+    current_task, current_trap_cx, current_user_token, exit_current_and_run_next,
+    suspend_current_and_run_next,
+};
+use crate::timer::set_next_trigger;
+use core::arch::asm;
+use riscv::register::{
+    mtvec::TrapMode,
+    scause::{self, Exception, Interrupt, Trap},
+    sie, stval, stvec,
+};
+
+pub use context::TrapContext;
+
+/// Route traps taken while already in supervisor mode to the kernel trap
+/// stub; the kernel should never fault on itself.
+fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __trap_from_kernel();
+    }
+    unsafe {
+        stvec::write(__trap_from_kernel as usize, TrapMode::Direct);
+    }
+}
+
+/// Route traps taken from user mode through the trampoline page
+fn set_user_trap_entry() {
+    unsafe {
+        stvec::write(TRAMPOLINE as usize, TrapMode::Direct);
+    }
+}
+
+/// Enable the supervisor timer interrupt so the scheduler can preempt a
+/// task that has run for a full time slice.
+pub fn enable_timer_interrupt() {
+    unsafe {
+        sie::set_stimer();
+    }
+}
+
+/// Set up trap handling for this hart: user traps route through the
+/// trampoline, kernel traps panic.
+pub fn init() {
+    set_kernel_trap_entry();
+}
+
+#[no_mangle]
+/// Handle a trap taken from user space
+pub fn trap_handler() -> ! {
+    set_kernel_trap_entry();
+    let scause = scause::read();
+    let stval = stval::read();
+    match scause.cause() {
+        Trap::Exception(Exception::UserEnvCall) => {
+            let mut cx = current_trap_cx();
+            cx.sepc += 4;
+            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]);
+            // `sys_exec` gives the current task a new address space and with
+            // it a new trap context page, so the trap context pointer has to
+            // be refetched before writing the return value back.
+            cx = current_trap_cx();
+            cx.x[10] = match result {
+                Ok(ret) => ret as usize,
+                Err(_) => -1isize as usize,
+            };
+        }
+        Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::InstructionPageFault) => {
+            // A page fault on a mapped-but-not-yet-backed mmap region (see
+            // `processor::allocate_memory`) is expected: hand it one frame
+            // and resume. Anything else is a genuine fault.
+            let required_perm = match scause.cause() {
+                Trap::Exception(Exception::StorePageFault) => MapPermission::W,
+                Trap::Exception(Exception::InstructionPageFault) => MapPermission::X,
+                _ => MapPermission::R,
+            };
+            let handled = current_task()
+                .unwrap()
+                .inner_exclusive_access()
+                .memory_set
+                .try_lazy_alloc(VirtAddr::from(stval), required_perm);
+            if !handled {
+                trace!(
+                    "[kernel] {:?} in application, bad addr = {:#x}, core dumped.",
+                    scause.cause(),
+                    stval,
+                );
+                exit_current_and_run_next(-2);
+            }
+        }
+        Trap::Exception(Exception::StoreFault)
+        | Trap::Exception(Exception::InstructionFault)
+        | Trap::Exception(Exception::LoadFault) => {
+            trace!(
+                "[kernel] {:?} in application, bad addr = {:#x}, core dumped.",
+                scause.cause(),
+                stval,
+            );
+            exit_current_and_run_next(-2);
+        }
+        Trap::Exception(Exception::IllegalInstruction) => {
+            trace!("[kernel] IllegalInstruction in application, core dumped.");
+            exit_current_and_run_next(-3);
+        }
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            set_next_trigger();
+            suspend_current_and_run_next();
+        }
+        _ => {
+            panic!(
+                "Unsupported trap {:?}, stval = {:#x}!",
+                scause.cause(),
+                stval
+            );
+        }
+    }
+    trap_return();
+}
+
+#[no_mangle]
+/// Return to user space, restoring the current task's `TrapContext`
+pub fn trap_return() -> ! {
+    set_user_trap_entry();
+    let trap_cx_ptr = TRAP_CONTEXT;
+    let user_satp = current_user_token();
+    extern "C" {
+        fn __alltraps();
+        fn __restore();
+    }
+    let restore_va = __restore as usize - __alltraps as usize + TRAMPOLINE;
+    unsafe {
+        asm!(
+            "fence.i",
+            "jr {restore_va}",
+            restore_va = in(reg) restore_va,
+            in("a0") trap_cx_ptr,
+            in("a1") user_satp,
+            options(noreturn)
+        );
+    }
+}
+
+#[no_mangle]
+/// A trap taken while already running in supervisor mode; this should never
+/// happen in a correctly implemented kernel.
+pub fn trap_from_kernel() -> ! {
+    panic!("a trap from kernel!");
+}